@@ -1,43 +1,138 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key as AesKey, Nonce};
 use askama::Template;
 use axum::{
-    extract::{Path, State},
+    extract::{FromRef, Path, Query, State},
     http::Request,
     middleware::Next,
+    response::sse::{Event, KeepAlive, Sse},
     response::{Html, IntoResponse, Redirect},
     routing, Form, Router,
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
+use axum_extra::extract::cookie::{Cookie, Key, SignedCookieJar};
 use chrono::{prelude::*, Duration};
+use dashmap::DashMap;
 use dotenv::dotenv;
 use error_chain::error_chain;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use reqwest::StatusCode;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::env;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
 use tower_http::services::{ServeDir, ServeFile};
 
+/// How long a session stays valid after its last use; refreshed on every authorized request.
+const SESSION_TTL_HOURS: i64 = 12;
+
+/// How often the background poller re-fetches tasks from Habitica when `POLL_INTERVAL_SECS`
+/// isn't set in the environment.
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 60;
+
 error_chain! {
     foreign_links {
         Io(std::io::Error);
         HttpRequest(reqwest::Error);
+        Json(serde_json::Error);
+    }
+    errors {
+        HabiticaRequestFailed(status: StatusCode) {
+            description("Habitica API request failed")
+            display("Habitica API request failed with status {}", status)
+        }
+        CredentialEncryptionFailed {
+            description("failed to encrypt Habitica credentials")
+            display("failed to encrypt Habitica credentials")
+        }
+        CredentialDecryptionFailed {
+            description("failed to decrypt Habitica credentials")
+            display("failed to decrypt Habitica credentials")
+        }
     }
 }
 
+/// Path of the on-disk snapshot used to keep serving `root` while Habitica is unreachable.
+const TASK_CACHE_PATH: &str = "./task_cache.json";
+/// Path of the on-disk outbox of completions that couldn't be sent to Habitica yet.
+const OUTBOX_PATH: &str = "./outbox.json";
+/// Path of the on-disk store of per-user encrypted Habitica credentials.
+const CREDENTIALS_PATH: &str = "./credentials.json";
+
 #[derive(Deserialize)]
 struct TaskResponse {
     data: Vec<Task>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct Task {
     _id: String,
     text: String,
+    #[serde(rename = "type")]
+    task_type: TaskType,
+    frequency: Option<Frequency>,
     repeat: Option<RepeatSchedule>,
+    #[serde(rename = "everyX")]
+    every_x: Option<i32>,
+    #[serde(rename = "startDate")]
+    start_date: Option<DateTime<Utc>>,
+    #[serde(rename = "daysOfMonth", default)]
+    days_of_month: Vec<u32>,
+    #[serde(rename = "weeksOfMonth", default)]
+    weeks_of_month: Vec<u32>,
+    /// To-do due date (habitica's `date` field). `None` means no deadline.
+    date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    checklist: Vec<ChecklistItem>,
+    /// Whether a habit can be scored up/down; absent for the other task types.
+    up: Option<bool>,
+    down: Option<bool>,
+    /// Habit "how well is this going" score, or the gold cost for a reward.
+    #[serde(default)]
+    value: f64,
+    /// Only present on dailies and to-dos; habits and rewards omit it entirely.
+    #[serde(default)]
+    completed: bool,
+}
+
+impl Task {
+    /// Habitica's coarse "how is this habit doing" color tier, derived from `value`.
+    /// Mirrors Habitica's own breakpoints for habit coloring.
+    fn habit_color(&self) -> &'static str {
+        match self.value {
+            v if v <= -20.0 => "worst",
+            v if v <= -10.0 => "worse",
+            v if v < 0.0 => "bad",
+            v if v < 1.0 => "neutral",
+            v if v < 5.0 => "good",
+            v if v < 10.0 => "better",
+            _ => "best",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct ChecklistItem {
+    text: String,
     completed: bool,
 }
 
-#[derive(Deserialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TaskType {
+    Habit,
+    Daily,
+    Todo,
+    Reward,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
 struct RepeatSchedule {
     su: bool,
     m: bool,
@@ -48,6 +143,187 @@ struct RepeatSchedule {
     s: bool,
 }
 
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// Last known-good snapshot of a user's due tasks, persisted so `root` can still render
+/// something useful when Habitica is unreachable.
+#[derive(Deserialize, Serialize, Clone)]
+struct TaskCache {
+    tasks: Vec<Task>,
+    day_start_hour: i32,
+}
+
+/// A completion that couldn't be sent to Habitica yet, queued for the background drainer.
+#[derive(Deserialize, Serialize, Clone)]
+struct OutboxEntry {
+    task_id: String,
+    direction: String,
+    /// Send attempts so far. Habitica's score endpoint increments on every call rather than
+    /// being idempotent, so a "succeeded server-side but the response was lost" retry can
+    /// double-score a task; this bounds how many times we'll risk that before giving up.
+    #[serde(default)]
+    attempts: u32,
+}
+
+/// A user's own Habitica user-id and API token, as entered at login.
+#[derive(Deserialize, Serialize, Clone)]
+struct HabiticaCredentials {
+    user_id: String,
+    api_key: String,
+}
+
+/// `HabiticaCredentials`, encrypted at rest with `AppState::master_key`.
+#[derive(Deserialize, Serialize, Clone)]
+struct EncryptedCredentials {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+/// Derives a 256-bit AES key from the `MASTER_SECRET` environment value.
+fn derive_master_key(master_secret: &str) -> Aes256Gcm {
+    let digest = Sha256::digest(master_secret.as_bytes());
+    Aes256Gcm::new(AesKey::<Aes256Gcm>::from_slice(&digest))
+}
+
+fn encrypt_credentials(
+    master_key: &Aes256Gcm,
+    credentials: &HabiticaCredentials,
+) -> Result<EncryptedCredentials> {
+    let plaintext = serde_json::to_vec(credentials)?;
+    let mut nonce_bytes = [0u8; 12];
+    rand::thread_rng().fill(&mut nonce_bytes);
+    match master_key.encrypt(Nonce::from_slice(&nonce_bytes), plaintext.as_ref()) {
+        Ok(ciphertext) => Ok(EncryptedCredentials {
+            nonce: nonce_bytes.to_vec(),
+            ciphertext,
+        }),
+        Err(_) => Err(ErrorKind::CredentialEncryptionFailed.into()),
+    }
+}
+
+fn decrypt_credentials(
+    master_key: &Aes256Gcm,
+    encrypted: &EncryptedCredentials,
+) -> Result<HabiticaCredentials> {
+    let plaintext = match master_key.decrypt(Nonce::from_slice(&encrypted.nonce), encrypted.ciphertext.as_ref()) {
+        Ok(plaintext) => plaintext,
+        Err(_) => return Err(ErrorKind::CredentialDecryptionFailed.into()),
+    };
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+async fn read_credential_store() -> HashMap<String, EncryptedCredentials> {
+    match tokio::fs::read(CREDENTIALS_PATH).await {
+        Ok(contents) => serde_json::from_slice(&contents).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+async fn write_credential_store(store: &HashMap<String, EncryptedCredentials>) -> Result<()> {
+    let contents = serde_json::to_vec(store)?;
+    tokio::fs::write(CREDENTIALS_PATH, contents).await?;
+    Ok(())
+}
+
+/// Encrypts and persists `credentials` under `username`, overwriting any existing entry.
+async fn store_habitica_credentials(
+    state: &AppState,
+    username: &str,
+    credentials: HabiticaCredentials,
+) -> Result<()> {
+    let encrypted = encrypt_credentials(&state.master_key, &credentials)?;
+    let mut store = state.credentials.write().await;
+    store.insert(username.to_string(), encrypted);
+    write_credential_store(&store).await
+}
+
+/// Looks up and decrypts the Habitica credentials stored for `username`, if any.
+async fn decrypt_user_credentials(state: &AppState, username: &str) -> Option<HabiticaCredentials> {
+    let encrypted = state.credentials.read().await.get(username)?.clone();
+    decrypt_credentials(&state.master_key, &encrypted).ok()
+}
+
+/// Server-side record for a logged-in session, keyed by the id stored in the signed cookie.
+struct Session {
+    username: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Generates a fresh session for `username`, registers it in `state.sessions`, and returns its id.
+fn create_session(state: &AppState, username: String) -> String {
+    let session_id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    state.sessions.insert(
+        session_id.clone(),
+        Session {
+            username,
+            expires_at: Utc::now() + Duration::hours(SESSION_TTL_HOURS),
+        },
+    );
+    session_id
+}
+
+/// Returns the local username owned by a still-live session stored under the `session_id` cookie.
+async fn current_username(state: &AppState, jar: &SignedCookieJar) -> Option<String> {
+    let session_id = jar.get("session_id")?;
+    state
+        .sessions
+        .get(session_id.value())
+        .filter(|session| session.expires_at > Utc::now())
+        .map(|session| session.username.clone())
+}
+
+/// Checks whether `session_id` is a live session, refreshing its expiry on success
+/// (sliding expiration) and evicting it if it has expired.
+fn touch_session(state: &AppState, session_id: &str) -> bool {
+    let now = Utc::now();
+    if let Some(mut session) = state.sessions.get_mut(session_id) {
+        if session.expires_at > now {
+            session.expires_at = now + Duration::hours(SESSION_TTL_HOURS);
+            return true;
+        }
+    }
+    state.sessions.remove(session_id);
+    false
+}
+
+/// Live-update events pushed to connected readers over `/events`, scoped to the reader
+/// they belong to so one account's task ids and refreshes never reach another's stream.
+#[derive(Clone, Debug)]
+struct AppEvent {
+    username: String,
+    kind: AppEventKind,
+}
+
+#[derive(Clone, Debug)]
+enum AppEventKind {
+    TaskCompleted { task_id: String },
+    TasksRefreshed,
+    DayStarted,
+}
+
+impl AppEvent {
+    fn into_sse_event(self) -> Event {
+        match self.kind {
+            AppEventKind::TaskCompleted { task_id } => {
+                Event::default().event("task_completed").data(task_id)
+            }
+            AppEventKind::TasksRefreshed => Event::default().event("tasks_refreshed").data(""),
+            AppEventKind::DayStarted => Event::default().event("day_started").data(""),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct UserResponse {
     data: User,
@@ -68,13 +344,31 @@ async fn get_due_tasks(api_key: &str, user_id: &str, day_start_hour: i32) -> Res
     let all_tasks = get_all_tasks(api_key, user_id).await?;
     Ok(all_tasks
         .into_iter()
-        .filter(|task| {
-            task.repeat
-                .map_or(true, |schedule| task_due_today(schedule, day_start_hour))
-        })
+        .filter(|task| !task.completed && task_is_due(task, day_start_hour))
         .collect())
 }
 
+/// Whether `task` should show up in the due list, by task type: dailies follow their
+/// repeat schedule, to-dos show once their due date has arrived (or always, if unset),
+/// and habits/rewards are always actionable.
+fn task_is_due(task: &Task, day_start_hour: i32) -> bool {
+    match task.task_type {
+        TaskType::Daily => task_due_today(task, day_start_hour),
+        TaskType::Todo => todo_is_due(task, day_start_hour),
+        TaskType::Habit | TaskType::Reward => true,
+    }
+}
+
+fn todo_is_due(task: &Task, day_start_hour: i32) -> bool {
+    match task.date {
+        Some(due_date) => {
+            let today = day_start_adjusted_date(chrono::Local::now(), day_start_hour);
+            day_start_adjusted_date(due_date.with_timezone(&Local), day_start_hour) <= today
+        }
+        None => true,
+    }
+}
+
 async fn get_all_tasks(api_key: &str, user_id: &str) -> Result<Vec<Task>> {
     let client = reqwest::Client::new();
     let res = client
@@ -83,10 +377,13 @@ async fn get_all_tasks(api_key: &str, user_id: &str) -> Result<Vec<Task>> {
         .header("x-api-user", user_id)
         .header("x-api-key", api_key)
         .send()
-        .await
-        .unwrap();
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(ErrorKind::HabiticaRequestFailed(res.status()).into());
+    }
 
-    Ok(res.json::<TaskResponse>().await.unwrap().data)
+    Ok(res.json::<TaskResponse>().await?.data)
 }
 
 async fn score_task(
@@ -100,7 +397,6 @@ async fn score_task(
         "https://habitica.com/api/v3/tasks/{}/score/{}",
         task_id, direction
     );
-    // let query_string = "https://habitica.com/api/v3/tasks/d434eb4e-ca94-40f5-9794-81ae805990fa/score/up";
     let res = client
         .post(query_string)
         .header("x-client", "test-app")
@@ -108,19 +404,72 @@ async fn score_task(
         .header("x-api-key", api_key)
         .header("Content-Length", 0)
         .send()
-        .await
-        .unwrap();
+        .await?;
+
+    if res.status().is_success() {
+        Ok(res.status())
+    } else {
+        Err(ErrorKind::HabiticaRequestFailed(res.status()).into())
+    }
+}
+
+fn task_due_today(task: &Task, day_start_hour: i32) -> bool {
+    let today = day_start_adjusted_date(chrono::Local::now(), day_start_hour);
+
+    let start_date = match task.start_date {
+        Some(start_date) => day_start_adjusted_date(start_date.with_timezone(&Local), day_start_hour),
+        // Habitica always sends a startDate for recurring tasks; without one we have no
+        // basis for a schedule, so treat the task as always due.
+        None => return true,
+    };
 
-    match res.status() {
-        StatusCode::OK => Ok(res.status()),
-        _ => panic!("Got Error code {}", res.status()),
+    let every_x = task.every_x.unwrap_or(1).max(1) as i64;
+
+    match task.frequency {
+        Some(Frequency::Daily) => {
+            let days_elapsed = (today - start_date).num_days();
+            days_elapsed >= 0 && days_elapsed % every_x == 0
+        }
+        Some(Frequency::Weekly) => {
+            let weekday_due = task
+                .repeat
+                .is_some_and(|schedule| repeat_schedule_weekday(schedule, today.weekday()));
+            let weeks_elapsed = (today - start_date).num_days().div_euclid(7);
+            weekday_due && weeks_elapsed >= 0 && weeks_elapsed % every_x == 0
+        }
+        Some(Frequency::Monthly) => {
+            let months_elapsed = months_between(start_date, today);
+            if months_elapsed < 0 || months_elapsed % every_x != 0 {
+                return false;
+            }
+            if !task.days_of_month.is_empty() {
+                task.days_of_month.contains(&today.day())
+            } else {
+                let weekday_due = task
+                    .repeat
+                    .is_some_and(|schedule| repeat_schedule_weekday(schedule, today.weekday()));
+                weekday_due && task.weeks_of_month.contains(&((today.day0()) / 7))
+            }
+        }
+        Some(Frequency::Yearly) => {
+            let years_elapsed = i64::from(today.year() - start_date.year());
+            years_elapsed >= 0
+                && years_elapsed % every_x == 0
+                && today.month() == start_date.month()
+                && today.day() == start_date.day()
+        }
+        None => task
+            .repeat
+            .is_none_or(|schedule| repeat_schedule_weekday(schedule, today.weekday())),
     }
 }
 
-fn task_due_today(repeat_schedule: RepeatSchedule, day_start_hour: i32) -> bool {
-    let current_date_with_offset = chrono::Local::now() - Duration::hours(day_start_hour.into());
-    let day_of_week = current_date_with_offset.weekday();
-    match day_of_week {
+fn day_start_adjusted_date<Tz: chrono::TimeZone>(date_time: DateTime<Tz>, day_start_hour: i32) -> NaiveDate {
+    (date_time - Duration::hours(day_start_hour.into())).date_naive()
+}
+
+fn repeat_schedule_weekday(repeat_schedule: RepeatSchedule, weekday: chrono::Weekday) -> bool {
+    match weekday {
         chrono::Weekday::Sun => repeat_schedule.su,
         chrono::Weekday::Mon => repeat_schedule.m,
         chrono::Weekday::Tue => repeat_schedule.t,
@@ -131,7 +480,13 @@ fn task_due_today(repeat_schedule: RepeatSchedule, day_start_hour: i32) -> bool
     }
 }
 
-async fn get_day_start_hour(api_key: &str, user_id: &str) -> i32 {
+/// Number of calendar months from `start`'s month to `end`'s month; the day of the month is
+/// ignored, and the result is negative when `end` falls in an earlier month than `start`.
+fn months_between(start: NaiveDate, end: NaiveDate) -> i64 {
+    i64::from(end.year() - start.year()) * 12 + i64::from(end.month()) - i64::from(start.month())
+}
+
+async fn get_day_start_hour(api_key: &str, user_id: &str) -> Result<i32> {
     let client = reqwest::Client::new();
     let res = client
         .get("https://habitica.com/api/v3/user?userFields=preferences")
@@ -140,11 +495,264 @@ async fn get_day_start_hour(api_key: &str, user_id: &str) -> i32 {
         .header("x-api-key", api_key)
         .header("Content-Length", 0)
         .send()
-        .await
-        .unwrap();
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(ErrorKind::HabiticaRequestFailed(res.status()).into());
+    }
+
+    let data = res.json::<UserResponse>().await?.data;
+    Ok(data.preferences.day_start)
+}
+
+/// Fetches `username`'s latest due tasks from Habitica and, on success, refreshes the
+/// on-disk cache that `root` falls back to when Habitica can't be reached.
+async fn fetch_and_cache_tasks(
+    state: &AppState,
+    username: &str,
+    credentials: &HabiticaCredentials,
+) -> Result<Vec<Task>> {
+    let day_start_hour = get_day_start_hour(&credentials.api_key, &credentials.user_id).await?;
+    let tasks = get_due_tasks(&credentials.api_key, &credentials.user_id, day_start_hour).await?;
+
+    let cache = TaskCache {
+        tasks: tasks.clone(),
+        day_start_hour,
+    };
+    {
+        let mut cache_map = state.task_cache.write().await;
+        cache_map.insert(username.to_string(), cache);
+        if let Err(err) = write_task_cache(&cache_map).await {
+            eprintln!("failed to persist task cache to disk: {err}");
+        }
+    }
+
+    Ok(tasks)
+}
+
+async fn write_task_cache(cache: &HashMap<String, TaskCache>) -> Result<()> {
+    let contents = serde_json::to_vec(cache)?;
+    tokio::fs::write(TASK_CACHE_PATH, contents).await?;
+    Ok(())
+}
+
+async fn read_task_cache() -> HashMap<String, TaskCache> {
+    match tokio::fs::read(TASK_CACHE_PATH).await {
+        Ok(contents) => serde_json::from_slice(&contents).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+async fn write_outbox(outbox: &HashMap<String, Vec<OutboxEntry>>) -> Result<()> {
+    let contents = serde_json::to_vec(outbox)?;
+    tokio::fs::write(OUTBOX_PATH, contents).await?;
+    Ok(())
+}
+
+async fn read_outbox() -> HashMap<String, Vec<OutboxEntry>> {
+    match tokio::fs::read(OUTBOX_PATH).await {
+        Ok(contents) => serde_json::from_slice(&contents).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+async fn enqueue_outbox_entry(state: &AppState, username: &str, entry: OutboxEntry) {
+    let mut outbox = state.outbox.lock().await;
+    outbox.entry(username.to_string()).or_default().push(entry);
+    if let Err(err) = write_outbox(&outbox).await {
+        eprintln!("failed to persist outbox to disk: {err}");
+    }
+}
+
+const OUTBOX_BASE_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+const OUTBOX_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(64);
+const OUTBOX_IDLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// Habitica's score endpoint increments on every call instead of being idempotent, so a
+/// retry after a lost response risks double-scoring the task. We cap attempts rather than
+/// retrying forever, trading "might miss a completion" for "might double-score it" only a
+/// bounded number of times.
+const OUTBOX_MAX_ATTEMPTS: u32 = 8;
 
-    let data = res.json::<UserResponse>().await.unwrap().data;
-    data.preferences.day_start
+/// Counts occurrences of each distinct (task_id, direction, attempts) triple, so a batch of
+/// entries can be matched against the live outbox one-for-one instead of by predicate, which
+/// would over-match identical duplicate completions.
+fn outbox_entry_counts(entries: &[OutboxEntry]) -> HashMap<(String, String, u32), usize> {
+    let mut counts = HashMap::new();
+    for entry in entries {
+        *counts
+            .entry((entry.task_id.clone(), entry.direction.clone(), entry.attempts))
+            .or_insert(0) += 1;
+    }
+    counts
+}
+
+/// If `entry` matches a remaining count in `counts`, consumes one occurrence and returns true.
+fn consume_match(counts: &mut HashMap<(String, String, u32), usize>, entry: &OutboxEntry) -> bool {
+    let key = (entry.task_id.clone(), entry.direction.clone(), entry.attempts);
+    match counts.get_mut(&key) {
+        Some(count) if *count > 0 => {
+            *count -= 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Background task that retries queued completions against Habitica for every user, backing
+/// off exponentially (capped at `OUTBOX_MAX_BACKOFF`) while anything remains unacknowledged,
+/// and giving up on an entry after `OUTBOX_MAX_ATTEMPTS` rather than resending indefinitely.
+fn spawn_outbox_drainer(state: AppState) {
+    tokio::spawn(async move {
+        let mut backoff = OUTBOX_BASE_BACKOFF;
+        loop {
+            let pending = state.outbox.lock().await.clone();
+            if pending.values().all(Vec::is_empty) {
+                tokio::time::sleep(OUTBOX_IDLE_INTERVAL).await;
+                continue;
+            }
+
+            // Entries to drop from the live outbox (sent successfully, or abandoned after
+            // too many attempts) and entries whose attempt count needs bumping in place,
+            // each keyed by username and matched against the original (pre-increment) entry.
+            let mut to_remove: HashMap<String, Vec<OutboxEntry>> = HashMap::new();
+            let mut to_bump: HashMap<String, Vec<OutboxEntry>> = HashMap::new();
+            for (username, entries) in pending {
+                let Some(credentials) = decrypt_user_credentials(&state, &username).await else {
+                    continue;
+                };
+
+                let mut user_removed = Vec::new();
+                let mut user_bumped = Vec::new();
+                for entry in entries {
+                    let scored = score_task(
+                        &credentials.api_key,
+                        &credentials.user_id,
+                        &entry.task_id,
+                        &entry.direction,
+                    )
+                    .await;
+                    if scored.is_ok() {
+                        user_removed.push(entry);
+                        continue;
+                    }
+
+                    if entry.attempts + 1 >= OUTBOX_MAX_ATTEMPTS {
+                        eprintln!(
+                            "giving up on outbox entry {} {} for {username} after {} attempts \
+                             (delivery status against Habitica is unknown)",
+                            entry.task_id,
+                            entry.direction,
+                            entry.attempts + 1
+                        );
+                        user_removed.push(entry);
+                    } else {
+                        user_bumped.push(entry);
+                    }
+                }
+                if !user_removed.is_empty() {
+                    to_remove.insert(username.clone(), user_removed);
+                }
+                if !user_bumped.is_empty() {
+                    to_bump.insert(username, user_bumped);
+                }
+            }
+
+            // Apply removals and attempt bumps against the *live* outbox under a held lock,
+            // matching by (task_id, direction, attempts) and consuming exactly one entry per
+            // match — so completions enqueued by `clicked` mid-drain, and duplicate
+            // completions of the same task/direction, aren't clobbered by the stale snapshot
+            // or over-deleted/over-bumped by a predicate that matches every duplicate at once.
+            let mut outbox = state.outbox.lock().await;
+            for (username, removed) in &to_remove {
+                if let Some(entries) = outbox.get_mut(username) {
+                    let mut counts = outbox_entry_counts(removed);
+                    entries.retain(|entry| !consume_match(&mut counts, entry));
+                    if entries.is_empty() {
+                        outbox.remove(username);
+                    }
+                }
+            }
+            for (username, bumped) in &to_bump {
+                if let Some(entries) = outbox.get_mut(username) {
+                    let mut counts = outbox_entry_counts(bumped);
+                    for entry in entries.iter_mut() {
+                        if consume_match(&mut counts, entry) {
+                            entry.attempts += 1;
+                        }
+                    }
+                }
+            }
+            let any_remaining = !outbox.values().all(Vec::is_empty);
+            let snapshot = outbox.clone();
+            drop(outbox);
+
+            if let Err(err) = write_outbox(&snapshot).await {
+                eprintln!("failed to persist outbox to disk: {err}");
+            }
+
+            if any_remaining {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(OUTBOX_MAX_BACKOFF);
+            } else {
+                backoff = OUTBOX_BASE_BACKOFF;
+                tokio::time::sleep(OUTBOX_IDLE_INTERVAL).await;
+            }
+        }
+    });
+}
+
+/// Background task that periodically re-fetches every user's tasks from Habitica so changes
+/// made on other devices propagate to connected readers, and publishes a `DayStarted` event
+/// whenever a user's day-start-adjusted date rolls over so the due list refreshes at midnight.
+fn spawn_task_poller(state: AppState, poll_interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut last_day_by_user: HashMap<String, NaiveDate> = HashMap::new();
+        loop {
+            let usernames: Vec<String> = state.credentials.read().await.keys().cloned().collect();
+            for username in usernames {
+                let Some(credentials) = decrypt_user_credentials(&state, &username).await else {
+                    continue;
+                };
+
+                match get_day_start_hour(&credentials.api_key, &credentials.user_id).await {
+                    Ok(day_start_hour) => {
+                        let today = day_start_adjusted_date(chrono::Local::now(), day_start_hour);
+                        if last_day_by_user
+                            .get(&username)
+                            .is_some_and(|day| *day != today)
+                        {
+                            state
+                                .events
+                                .send(AppEvent {
+                                    username: username.clone(),
+                                    kind: AppEventKind::DayStarted,
+                                })
+                                .ok();
+                        }
+                        last_day_by_user.insert(username.clone(), today);
+                    }
+                    Err(err) => {
+                        eprintln!("failed to check day-start boundary for {username}: {err}")
+                    }
+                }
+
+                if fetch_and_cache_tasks(&state, &username, &credentials)
+                    .await
+                    .is_ok()
+                {
+                    state
+                        .events
+                        .send(AppEvent {
+                            username: username.clone(),
+                            kind: AppEventKind::TasksRefreshed,
+                        })
+                        .ok();
+                }
+            }
+
+            tokio::time::sleep(poll_interval).await;
+        }
+    });
 }
 
 #[derive(Template)]
@@ -153,80 +761,199 @@ struct IndexTemplate {
     tasks: Vec<Task>,
 }
 
-async fn root(State(state): State<AppState>) -> impl IntoResponse {
-    let day_start_hour = get_day_start_hour(&state.habitica_api_key, &state.habitica_user_id).await;
-    let tasks = get_due_tasks(
-        &state.habitica_api_key,
-        &state.habitica_user_id,
-        day_start_hour,
-    )
-    .await
-    .unwrap();
-    let index_html = IndexTemplate { tasks }.render().unwrap();
-    (StatusCode::OK, Html(index_html).into_response())
+async fn root(State(state): State<AppState>, jar: SignedCookieJar) -> impl IntoResponse {
+    let Some(username) = current_username(&state, &jar).await else {
+        return (StatusCode::UNAUTHORIZED, Html("Not logged in.").into_response());
+    };
+    let Some(credentials) = decrypt_user_credentials(&state, &username).await else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Html("No Habitica credentials on file for this user.").into_response(),
+        );
+    };
+
+    match fetch_and_cache_tasks(&state, &username, &credentials).await {
+        Ok(tasks) => {
+            let index_html = IndexTemplate { tasks }.render().unwrap();
+            (StatusCode::OK, Html(index_html).into_response())
+        }
+        Err(err) => {
+            eprintln!("failed to refresh tasks from Habitica, falling back to cache: {err}");
+            match state.task_cache.read().await.get(&username).cloned() {
+                Some(cache) => {
+                    let index_html = IndexTemplate { tasks: cache.tasks }.render().unwrap();
+                    (StatusCode::OK, Html(index_html).into_response())
+                }
+                None => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Html("Habitica is unreachable and no cached tasks are available yet.")
+                        .into_response(),
+                ),
+            }
+        }
+    }
 }
 
-async fn clicked(State(state): State<AppState>, Path(task_id): Path<String>) {
-    println!("clicked {task_id}!");
-    score_task(
-        &state.habitica_api_key,
-        &state.habitica_user_id,
+/// Query params for `POST /complete/:id`. `direction` defaults to `"up"` so existing callers
+/// that only ever completed a task (the pre-habit-support behavior) keep working unchanged.
+#[derive(Deserialize)]
+struct CompleteParams {
+    #[serde(default = "default_direction")]
+    direction: String,
+}
+
+fn default_direction() -> String {
+    "up".to_string()
+}
+
+async fn clicked(
+    State(state): State<AppState>,
+    jar: SignedCookieJar,
+    Path(task_id): Path<String>,
+    Query(params): Query<CompleteParams>,
+) -> StatusCode {
+    let direction = params.direction;
+    if direction != "up" && direction != "down" {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    let Some(username) = current_username(&state, &jar).await else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let Some(credentials) = decrypt_user_credentials(&state, &username).await else {
+        eprintln!("clicked {task_id} but no Habitica credentials are on file for {username}");
+        return StatusCode::BAD_REQUEST;
+    };
+
+    println!("clicked {task_id} {direction}!");
+    let scored = score_task(
+        &credentials.api_key,
+        &credentials.user_id,
         &task_id,
-        "up",
+        &direction,
     )
-    .await
-    .unwrap();
+    .await;
+
+    if let Err(err) = scored {
+        eprintln!("failed to score task {task_id}, queuing for retry: {err}");
+        enqueue_outbox_entry(
+            &state,
+            &username,
+            OutboxEntry {
+                task_id: task_id.clone(),
+                direction,
+                attempts: 0,
+            },
+        )
+        .await;
+    }
+
+    state
+        .events
+        .send(AppEvent {
+            username,
+            kind: AppEventKind::TaskCompleted { task_id },
+        })
+        .ok();
+    StatusCode::OK
+}
+
+async fn events(
+    State(state): State<AppState>,
+    jar: SignedCookieJar,
+) -> impl IntoResponse {
+    let Some(username) = current_username(&state, &jar).await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(move |event| {
+        match event {
+            Ok(event) if event.username == username => {
+                Some(Ok::<_, Infallible>(event.into_sse_event()))
+            }
+            _ => None,
+        }
+    });
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
 }
 
 #[derive(Deserialize)]
 struct Login {
     username: String,
     password: String,
+    #[serde(default)]
+    habitica_user_id: Option<String>,
+    #[serde(default)]
+    habitica_api_key: Option<String>,
 }
 
 async fn login(
     State(state): State<AppState>,
-    jar: CookieJar,
+    jar: SignedCookieJar,
     Form(login): Form<Login>,
 ) -> impl IntoResponse {
-    let cookie = jar.get("authorization_token");
-    if let Some(authz_token) = cookie {
-        if authz_token.value() == state.authz_token {
+    if let Some(session_cookie) = jar.get("session_id") {
+        if touch_session(&state, session_cookie.value()) {
             return (jar, Redirect::to("/"));
         }
     }
 
-    let form_username = login.username;
-    let form_password = login.password;
-    println!("{form_username}, {form_password}");
-
-    if form_username != state.username || form_password != state.password {
+    if state.readers.get(&login.username) != Some(&login.password) {
         return (jar, Redirect::to("/login"));
     }
 
+    match (login.habitica_user_id, login.habitica_api_key) {
+        (Some(user_id), Some(api_key)) => {
+            let credentials = HabiticaCredentials { user_id, api_key };
+            if let Err(err) = store_habitica_credentials(&state, &login.username, credentials).await {
+                eprintln!(
+                    "failed to store Habitica credentials for {}: {err}",
+                    login.username
+                );
+                return (jar, Redirect::to("/login"));
+            }
+        }
+        _ if state.credentials.read().await.contains_key(&login.username) => {}
+        _ => {
+            eprintln!(
+                "no Habitica credentials on file for {} and none were provided at login",
+                login.username
+            );
+            return (jar, Redirect::to("/login"));
+        }
+    }
+
     println!("AUTHENTICATED!");
 
-    let built_cookie = Cookie::build("authorization_token", state.authz_token)
+    let session_id = create_session(&state, login.username);
+    let built_cookie = Cookie::build("session_id", session_id)
         .path("/")
         .secure(true)
         .http_only(true)
-        .permanent()
         .finish();
 
     (jar.add(built_cookie), Redirect::to("/"))
 }
 
+async fn logout(State(state): State<AppState>, jar: SignedCookieJar) -> impl IntoResponse {
+    if let Some(session_cookie) = jar.get("session_id") {
+        state.sessions.remove(session_cookie.value());
+    }
+
+    (jar.remove(Cookie::named("session_id")), Redirect::to("/login"))
+}
+
 async fn authorization<B>(
     State(state): State<AppState>,
     req: Request<B>,
     next: Next<B>,
 ) -> impl IntoResponse {
-    let headers = req.headers();
-    let jar = CookieJar::from_headers(headers);
+    let jar = SignedCookieJar::from_headers(req.headers(), state.cookie_key.clone());
 
-    let cookie = jar.get("authorization_token");
-    if let Some(authz_token) = cookie {
-        if authz_token.value() == state.authz_token {
+    if let Some(session_cookie) = jar.get("session_id") {
+        if touch_session(&state, session_cookie.value()) {
             println!("authorized!");
             return next.run(req).await;
         }
@@ -235,13 +962,24 @@ async fn authorization<B>(
     Redirect::to("/login").into_response()
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 struct AppState {
-    habitica_api_key: String,
-    habitica_user_id: String,
-    username: String,
-    password: String,
-    authz_token: String,
+    /// Local reader accounts, username -> password, each with its own Habitica credential
+    /// slot so a household of readers can share one device without sharing one login.
+    readers: Arc<HashMap<String, String>>,
+    cookie_key: Key,
+    sessions: Arc<DashMap<String, Session>>,
+    master_key: Arc<Aes256Gcm>,
+    credentials: Arc<RwLock<HashMap<String, EncryptedCredentials>>>,
+    task_cache: Arc<RwLock<HashMap<String, TaskCache>>>,
+    outbox: Arc<Mutex<HashMap<String, Vec<OutboxEntry>>>>,
+    events: broadcast::Sender<AppEvent>,
+}
+
+impl FromRef<AppState> for Key {
+    fn from_ref(state: &AppState) -> Self {
+        state.cookie_key.clone()
+    }
 }
 
 #[tokio::main]
@@ -249,20 +987,36 @@ async fn main() -> Result<()> {
     dotenv().ok();
 
     let env_map: HashMap<_, _> = env::vars().collect();
+    let readers: HashMap<String, String> = serde_json::from_str(&env_map["READERS"])
+        .expect("READERS must be a JSON object of {\"username\": \"password\"}");
+    let (events_tx, _) = broadcast::channel(100);
     let state = AppState {
-        habitica_api_key: env_map["HABITICA_API_KEY"].clone(),
-        habitica_user_id: env_map["HABITICA_USER_ID"].clone(),
-        username: env_map["USERNAME"].clone(),
-        password: env_map["PASSWORD"].clone(),
-        authz_token: env_map["AUTHZ_TOKEN"].clone(),
+        readers: Arc::new(readers),
+        cookie_key: Key::generate(),
+        sessions: Arc::new(DashMap::new()),
+        master_key: Arc::new(derive_master_key(&env_map["MASTER_SECRET"])),
+        credentials: Arc::new(RwLock::new(read_credential_store().await)),
+        task_cache: Arc::new(RwLock::new(read_task_cache().await)),
+        outbox: Arc::new(Mutex::new(read_outbox().await)),
+        events: events_tx,
     };
 
-    println!("{:?}", state);
+    println!("Starting server for {} reader(s)", state.readers.len());
     println!("Current time: {}", chrono::Local::now());
 
+    let poll_interval_secs = env_map
+        .get("POLL_INTERVAL_SECS")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_POLL_INTERVAL_SECS);
+
+    spawn_outbox_drainer(state.clone());
+    spawn_task_poller(state.clone(), std::time::Duration::from_secs(poll_interval_secs));
+
     let router = Router::new()
         .route("/", routing::get(root))
         .route("/complete/:id", routing::post(clicked))
+        .route("/api/logout", routing::post(logout))
+        .route("/events", routing::get(events))
         .route_layer(axum::middleware::from_fn_with_state(
             state.clone(),
             authorization,
@@ -283,3 +1037,137 @@ async fn main() -> Result<()> {
         .unwrap();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_task(frequency: Option<Frequency>, start_date: DateTime<Utc>) -> Task {
+        Task {
+            _id: "task-1".to_string(),
+            text: "sample".to_string(),
+            task_type: TaskType::Daily,
+            frequency,
+            repeat: None,
+            every_x: Some(1),
+            start_date: Some(start_date),
+            days_of_month: Vec::new(),
+            weeks_of_month: Vec::new(),
+            date: None,
+            checklist: Vec::new(),
+            up: None,
+            down: None,
+            value: 0.0,
+            completed: false,
+        }
+    }
+
+    fn every_weekday() -> RepeatSchedule {
+        RepeatSchedule {
+            su: true,
+            m: true,
+            t: true,
+            w: true,
+            th: true,
+            f: true,
+            s: true,
+        }
+    }
+
+    #[test]
+    fn daily_task_due_on_every_x_interval() {
+        let today = day_start_adjusted_date(Local::now(), 0);
+        let start = Utc.from_utc_datetime(&(today - Duration::days(4)).and_hms_opt(0, 0, 0).unwrap());
+
+        let mut task = sample_task(Some(Frequency::Daily), start);
+        task.every_x = Some(2);
+        assert!(task_due_today(&task, 0), "4 days elapsed, everyX=2 should be due");
+
+        task.every_x = Some(3);
+        assert!(!task_due_today(&task, 0), "4 days elapsed, everyX=3 should not be due");
+    }
+
+    #[test]
+    fn monthly_task_due_by_days_of_month() {
+        let today = day_start_adjusted_date(Local::now(), 0);
+        let start = Utc.from_utc_datetime(&today.and_hms_opt(0, 0, 0).unwrap());
+
+        let mut task = sample_task(Some(Frequency::Monthly), start);
+        task.days_of_month = vec![today.day()];
+        assert!(task_due_today(&task, 0), "today's day-of-month is listed, should be due");
+
+        task.days_of_month = vec![if today.day() == 1 { 2 } else { 1 }];
+        assert!(!task_due_today(&task, 0), "today's day-of-month is not listed, should not be due");
+    }
+
+    #[test]
+    fn monthly_task_due_by_weeks_of_month() {
+        let today = day_start_adjusted_date(Local::now(), 0);
+        let start = Utc.from_utc_datetime(&today.and_hms_opt(0, 0, 0).unwrap());
+
+        let mut task = sample_task(Some(Frequency::Monthly), start);
+        task.repeat = Some(every_weekday());
+        task.weeks_of_month = vec![today.day0() / 7];
+        assert!(
+            task_due_today(&task, 0),
+            "today's weekday and week-of-month match, should be due"
+        );
+
+        task.weeks_of_month = vec![(today.day0() / 7) + 10];
+        assert!(
+            !task_due_today(&task, 0),
+            "week-of-month doesn't match, should not be due"
+        );
+    }
+
+    #[test]
+    fn yearly_task_due_only_on_anniversary() {
+        let today = day_start_adjusted_date(Local::now(), 0);
+        let start = Utc.from_utc_datetime(
+            &NaiveDate::from_ymd_opt(today.year() - 2, today.month(), today.day())
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+        );
+
+        let task = sample_task(Some(Frequency::Yearly), start);
+        assert!(task_due_today(&task, 0), "anniversary of start_date should be due");
+
+        let mut not_due = task.clone();
+        not_due.start_date = Some(Utc.from_utc_datetime(
+            &(today - Duration::days(1)).and_hms_opt(0, 0, 0).unwrap(),
+        ));
+        assert!(
+            !task_due_today(&not_due, 0),
+            "day after the anniversary should not be due"
+        );
+    }
+
+    #[test]
+    fn credentials_round_trip_through_encryption() {
+        let master_key = derive_master_key("test-master-secret");
+        let credentials = HabiticaCredentials {
+            user_id: "user-123".to_string(),
+            api_key: "api-key-456".to_string(),
+        };
+
+        let encrypted = encrypt_credentials(&master_key, &credentials).unwrap();
+        let decrypted = decrypt_credentials(&master_key, &encrypted).unwrap();
+
+        assert_eq!(decrypted.user_id, credentials.user_id);
+        assert_eq!(decrypted.api_key, credentials.api_key);
+    }
+
+    #[test]
+    fn decrypt_fails_with_wrong_master_key() {
+        let master_key = derive_master_key("correct-secret");
+        let wrong_key = derive_master_key("wrong-secret");
+        let credentials = HabiticaCredentials {
+            user_id: "user-123".to_string(),
+            api_key: "api-key-456".to_string(),
+        };
+
+        let encrypted = encrypt_credentials(&master_key, &credentials).unwrap();
+        assert!(decrypt_credentials(&wrong_key, &encrypted).is_err());
+    }
+}